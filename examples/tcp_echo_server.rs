@@ -63,6 +63,7 @@ impl Echo {
 
 impl Machine<Context> for Echo {
     type Creator = ConnCreator;
+    type Notification = Void;
 
     fn ready(self, _events: EventSet, _scope: &mut Scope<Context>)
         -> Response<Self, ConnCreator>
@@ -108,10 +109,10 @@ impl Machine<Context> for Echo {
     {
         unreachable!();
     }
-    fn wakeup(self, _scope: &mut Scope<Context>)
+    fn wakeup(self, msg: Void, _scope: &mut Scope<Context>)
         -> Response<Self, ConnCreator>
     {
-        unreachable!();
+        match msg {}
     }
 }
 
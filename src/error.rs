@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt;
+
+use mio::Token;
+
+
+/// The handler method that was running when a machine returned
+/// `Response::error(..)`.
+///
+/// Stored on `MachineError` purely for diagnostics (logging, tests);
+/// rotor never branches on it.
+pub const ORIGIN_READY: &'static str = "ready";
+pub const ORIGIN_TIMEOUT: &'static str = "timeout";
+pub const ORIGIN_WAKEUP: &'static str = "wakeup";
+pub const ORIGIN_SPAWNED: &'static str = "spawned";
+pub const ORIGIN_CREATE: &'static str = "create";
+pub const ORIGIN_SHUTDOWN: &'static str = "shutdown";
+
+/// Wraps a leaf error from `Response::error(..)` with the context of
+/// *where* it happened: which handler method was running and which
+/// `mio::Token` the machine occupied.
+///
+/// Modeled on tower's `ServiceError`: the original error is kept
+/// reachable through `source()` rather than swallowed, so `log_errors`
+/// (and anyone else inspecting the error) can print the full chain
+/// instead of just the leaf message.
+#[derive(Debug)]
+pub struct MachineError {
+    origin: &'static str,
+    token: Token,
+    cause: Box<Error>,
+}
+
+impl MachineError {
+    /// Wrap `cause`, recording that it happened in the `origin` handler
+    /// method for the machine at `token`.
+    pub fn new(origin: &'static str, token: Token, cause: Box<Error>)
+        -> MachineError
+    {
+        MachineError { origin: origin, token: token, cause: cause }
+    }
+
+    /// The handler method that was running (`"ready"`, `"timeout"`,
+    /// `"wakeup"`, `"spawned"`, `"create"` or `"shutdown"`) when the
+    /// error occurred.
+    ///
+    /// *Use only for unit tests and logging*: the counterpart of
+    /// `Response::cause()`.
+    pub fn origin(&self) -> &'static str {
+        self.origin
+    }
+
+    /// The `mio::Token` of the machine that produced the error.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "machine {:?} failed in `{}`: {}",
+            self.token, self.origin, self.cause)
+    }
+}
+
+impl Error for MachineError {
+    fn description(&self) -> &str {
+        "state machine returned Response::error(..)"
+    }
+    fn cause(&self) -> Option<&Error> {
+        Some(&*self.cause)
+    }
+    fn source(&self) -> Option<&(Error + 'static)> {
+        Some(&*self.cause)
+    }
+}
+
+/// Walks `err.source()` and logs every link in the chain at the warning
+/// level, innermost cause last.
+///
+/// Used by `decompose` in place of printing just the leaf error, so a
+/// `MachineError` wrapping several layers of causes is fully visible in
+/// the logs instead of only showing `"machine ... failed in ..."`.
+pub fn log_error_chain(err: &Error) {
+    warn!("{}", err);
+    let mut cause = err.source();
+    while let Some(e) = cause {
+        warn!("Caused by: {}", e);
+        cause = e.source();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fmt;
+
+    use mio::Token;
+
+    use super::{MachineError, ORIGIN_READY};
+
+    #[derive(Debug)]
+    struct LeafError;
+
+    impl fmt::Display for LeafError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "leaf error")
+        }
+    }
+
+    impl Error for LeafError {
+        fn description(&self) -> &str { "leaf error" }
+    }
+
+    #[test]
+    fn keeps_the_leaf_error_reachable_through_source() {
+        let err = MachineError::new(ORIGIN_READY, Token(5),
+            Box::new(LeafError));
+
+        assert_eq!(err.origin(), ORIGIN_READY);
+        assert_eq!(err.token(), Token(5));
+        assert_eq!(err.source().unwrap().to_string(), "leaf error");
+        assert_eq!(format!("{}", err),
+            "machine Token(5) failed in `ready`: leaf error");
+    }
+}
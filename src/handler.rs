@@ -0,0 +1,761 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::time::Duration;
+
+use mio::{EventLoop, EventSet, Timeout, Token};
+
+use {Creator, Machine, Scope, Time};
+use response::decompose_draining;
+use notify::Notifier;
+use timer::TimerHeap;
+use error::{MachineError, log_error_chain, ORIGIN_CREATE, ORIGIN_READY,
+    ORIGIN_SHUTDOWN, ORIGIN_SPAWNED, ORIGIN_TIMEOUT, ORIGIN_WAKEUP};
+
+
+/// The `mio::Handler` implementation that drives every state machine
+/// registered with the event loop.
+///
+/// In addition to normal event dispatch, `Handler` tracks a graceful
+/// shutdown ("drain") phase. Once `initiate_shutdown` is called:
+///
+/// * no new machine is allowed to spawn (`decompose` rejects
+///   `ResponseImpl::Spawn` and logs an error instead),
+/// * every live machine is given a chance to finish up via
+///   `Machine::shutdown`,
+/// * the loop keeps running until the last machine has gone away or the
+///   shutdown deadline fires, whichever comes first.
+pub struct Handler<C, M: Machine<C>> {
+    context: C,
+    machines: Vec<Option<M>>,
+    draining: bool,
+    shutdown_deadline: Option<Time>,
+    remaining: usize,
+    /// Tokens with a live `Response::interval(..)` timer and the period
+    /// needed to re-arm them.
+    reschedule: HashMap<Token, Duration>,
+    /// One bounded notify queue per machine, created lazily the first
+    /// time `Scope::notifier()` is called for a given token.
+    notifiers: HashMap<Token, Notifier<M::Notification>>,
+    /// `None` for the default, unthrottled `Handler::new` behavior.
+    throttle: Option<Throttle>,
+    /// Central min-heap of every machine's pending deadline; see
+    /// `timer::TimerHeap`.
+    timer: TimerHeap,
+    /// The single mio timeout currently armed for `timer`'s earliest
+    /// entry, if any. Replaced wholesale by `resync_timer` rather than
+    /// one timeout per machine.
+    armed_timer: Option<Timeout>,
+}
+
+/// Messages enqueued per-token default to this many pending entries
+/// before `Notifier::notify` starts returning `NotifyError::Full`.
+const DEFAULT_NOTIFY_CAPACITY: usize = 1024;
+
+/// Batches ready events and caps how many machine transitions are run
+/// per loop iteration, instead of draining every ready event as it
+/// arrives. Bounds syscall and wakeup churn under load.
+struct Throttle {
+    max_ops_per_tick: usize,
+    quantum: Duration,
+    queue: VecDeque<(Token, EventSet)>,
+    ops_this_tick: usize,
+}
+
+/// Reserved token used to re-arm the throttle's own `EventLoop::timeout_ms`
+/// wakeup. Never a valid index into `machines`, so `dispatch_timeout` must
+/// special-case it before touching the slab.
+const THROTTLE_TOKEN: Token = Token(::std::usize::MAX);
+
+/// Reserved token for the single mio timeout that drives `timer`, the
+/// central deadline heap. Real per-machine deadlines no longer register
+/// their own mio timeout; see `arm_timeout`/`resync_timer`.
+const TIMER_TOKEN: Token = Token(::std::usize::MAX - 1);
+
+/// Reserved token for the hard shutdown deadline armed by
+/// `initiate_shutdown`: forces `event_loop.shutdown()` once it fires,
+/// regardless of how many machines are still draining.
+const SHUTDOWN_TOKEN: Token = Token(::std::usize::MAX - 2);
+
+/// Converts a `Duration` into the millisecond count `EventLoop::timeout_ms`
+/// expects, rounding the sub-second remainder up to the nearest millisecond.
+fn duration_to_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + ((d.subsec_nanos() + 999_999) / 1_000_000) as u64
+}
+
+/// Milliseconds from now until `deadline`, clamped to zero (fire on the
+/// next tick) if `deadline` has already passed -- routine when the loop
+/// lags behind, or when a re-armed interval's `fired_at + period` is
+/// already behind `now` by the time it's re-armed.
+fn millis_until(deadline: Time) -> u64 {
+    let now = Time::now();
+    if deadline <= now {
+        0
+    } else {
+        duration_to_ms(deadline - now)
+    }
+}
+
+impl<C, M: Machine<C>> Handler<C, M> {
+    /// Returns true once `initiate_shutdown` has been called
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Returns the deadline passed to `initiate_shutdown`, if draining.
+    pub fn shutdown_deadline(&self) -> Option<Time> {
+        self.shutdown_deadline.clone()
+    }
+
+    /// Turn on throttled batch processing for an existing handler.
+    ///
+    /// At most `max_ops_per_tick` machine transitions (`ready` calls)
+    /// are driven per loop iteration; any events left over stay queued
+    /// for the next tick. If a tick's batch empties before the budget
+    /// is spent, the loop parks for up to `quantum` before polling mio
+    /// again, trading a little latency for smoother CPU usage.
+    pub fn with_throttle(&mut self, _event_loop: &mut EventLoop<Self>,
+        max_ops_per_tick: usize, quantum: Duration)
+    {
+        self.throttle = Some(Throttle {
+            max_ops_per_tick: max_ops_per_tick,
+            quantum: quantum,
+            queue: VecDeque::new(),
+            ops_this_tick: 0,
+        });
+    }
+
+    /// Enqueues a readiness event for `token`, to be processed (along
+    /// with the rest of the current batch) by `process_tick`.
+    ///
+    /// With no throttle configured this is the unthrottled default:
+    /// the event is processed immediately, matching `Handler::new`'s
+    /// existing one-event-at-a-time behavior.
+    fn queue_ready(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token, events: EventSet)
+    {
+        match self.throttle {
+            Some(ref mut throttle) => throttle.queue.push_back((token, events)),
+            None => self.dispatch_ready(event_loop, token, events),
+        }
+        self.process_tick(event_loop);
+    }
+
+    /// Processes queued ready events up to the per-tick budget.
+    ///
+    /// If the budget runs out before the queue is empty, the loop is
+    /// re-armed for an immediate next tick so the rest of the batch still
+    /// gets a turn. If the queue empties before the budget is spent, the
+    /// loop is parked for `quantum` instead, so it doesn't spin when
+    /// there's nothing left to do. Spawns do not go through the queue:
+    /// `spawn_machine` counts its `spawned()` dispatch against the same
+    /// per-tick counter directly.
+    fn process_tick(&mut self, event_loop: &mut EventLoop<Self>) {
+        let max_ops_per_tick = match self.throttle {
+            Some(ref t) => t.max_ops_per_tick,
+            None => return,
+        };
+        // Only park for `quantum` if this call actually drained
+        // something; a `THROTTLE_TOKEN` firing that finds the queue
+        // already empty means the burst is over, so we go fully idle
+        // instead of re-arming forever with nothing to do.
+        let mut processed_any = false;
+        loop {
+            let next = {
+                let throttle = self.throttle.as_mut().unwrap();
+                if throttle.ops_this_tick >= max_ops_per_tick {
+                    throttle.ops_this_tick = 0;
+                    event_loop.timeout_ms(THROTTLE_TOKEN, 0).ok();
+                    return;
+                }
+                throttle.queue.pop_front()
+            };
+            match next {
+                Some((token, events)) => {
+                    processed_any = true;
+                    self.throttle.as_mut().unwrap().ops_this_tick += 1;
+                    self.dispatch_ready(event_loop, token, events);
+                }
+                None => {
+                    self.throttle.as_mut().unwrap().ops_this_tick = 0;
+                    if processed_any {
+                        let quantum = self.throttle.as_ref().unwrap().quantum;
+                        event_loop.timeout_ms(THROTTLE_TOKEN,
+                            duration_to_ms(quantum)).ok();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Dispatches a single readiness event to `Machine::ready`.
+    fn dispatch_ready(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token, events: EventSet)
+    {
+        let machine = match self.machines[token.as_usize()].take() {
+            Some(m) => m,
+            None => return,
+        };
+        let response = {
+            let mut scope = Scope::new(token, &mut self.context, event_loop);
+            machine.ready(events, &mut scope)
+        };
+        let (result, spawn, mtimeout, period) = decompose_draining(
+            ORIGIN_READY, token, response, self.draining);
+        match result {
+            Ok(m) => {
+                self.machines[token.as_usize()] = Some(m);
+                if let Some(mut time) = mtimeout {
+                    if self.draining {
+                        time = self.clamp_to_shutdown(time);
+                    }
+                    self.arm_timeout(event_loop, token, time);
+                    match period {
+                        Some(period) => {
+                            self.reschedule.insert(token, period);
+                        }
+                        None => { self.reschedule.remove(&token); }
+                    }
+                }
+                if let Some(creator) = spawn {
+                    self.spawn_machine(event_loop, creator);
+                }
+            }
+            Err(_) => {
+                self.timer.cancel(token);
+                self.reschedule.remove(&token);
+                if self.draining {
+                    self.remaining -= 1;
+                    self.check_drained(event_loop);
+                }
+            }
+        }
+    }
+
+    /// Ask every live machine to wind down
+    ///
+    /// Walks the slab invoking `Machine::shutdown` on each entry still
+    /// alive. Machines that keep running (`Response::ok(..)`) are
+    /// automatically given `deadline` as their timeout, so a peer that
+    /// never finishes can't block shutdown forever. Once every machine
+    /// has decomposed to `Err(..)` -- or `deadline` is reached, whichever
+    /// is first -- `event_loop.shutdown()` is called.
+    pub fn initiate_shutdown(&mut self, event_loop: &mut EventLoop<Self>,
+        deadline: Time)
+    {
+        if self.draining {
+            return;
+        }
+        self.draining = true;
+        self.shutdown_deadline = Some(deadline);
+        event_loop.timeout_ms(SHUTDOWN_TOKEN, millis_until(deadline)).ok();
+        self.remaining = self.machines.iter()
+            .filter(|slot| slot.is_some())
+            .count();
+
+        for token in 0..self.machines.len() {
+            if self.machines[token].is_none() {
+                continue;
+            }
+            let machine = self.machines[token].take().unwrap();
+            // An interval re-arms itself from `fire_machine_timeout`'s
+            // `reschedule` lookup, bypassing whatever `shutdown()` just
+            // decided; drop it so the machine can't keep ticking past
+            // the drain deadline underneath the shutdown sweep.
+            self.reschedule.remove(&Token(token));
+            let response = {
+                let mut scope = Scope::new(Token(token),
+                    &mut self.context, event_loop);
+                machine.shutdown(&mut scope)
+            };
+            let (result, _spawn, mtimeout, _period) = decompose_draining(
+                ORIGIN_SHUTDOWN, Token(token), response, true);
+            match result {
+                Ok(m) => {
+                    self.machines[token] = Some(m);
+                    let time = self.clamp_to_shutdown(
+                        mtimeout.unwrap_or(deadline));
+                    self.arm_timeout(event_loop, Token(token), time);
+                }
+                Err(_) => {
+                    self.timer.cancel(Token(token));
+                    self.remaining -= 1;
+                }
+            }
+        }
+        self.check_drained(event_loop);
+    }
+
+    /// Called from the main dispatch loop whenever a machine decomposes
+    /// to `Err(..)` while we are draining; stops the loop once the last
+    /// one is gone.
+    fn check_drained(&mut self, event_loop: &mut EventLoop<Self>) {
+        if self.draining && self.remaining == 0 {
+            event_loop.shutdown();
+        }
+    }
+
+    /// Clamps `time` to the shutdown deadline while draining.
+    ///
+    /// Applies to every timer a machine arms for itself -- not just the
+    /// one `initiate_shutdown`'s initial sweep attaches -- so a machine
+    /// that renews its own deadline mid-drain still can't outlive the
+    /// global deadline and get killed by `SHUTDOWN_TOKEN` without ever
+    /// seeing its `Machine::timeout` grace callback.
+    fn clamp_to_shutdown(&self, time: Time) -> Time {
+        match self.shutdown_deadline {
+            Some(ref deadline) if time > *deadline => deadline.clone(),
+            _ => time,
+        }
+    }
+
+    /// Creates and registers the machine requested by a
+    /// `Response::spawn(..)`, giving it a fresh slab slot, then runs its
+    /// mandatory first dispatch, `Machine::spawned`, and registers the
+    /// `Response` it returns exactly the way `dispatch_ready` registers
+    /// `Machine::ready`'s: timer/reschedule armed, and any further spawn
+    /// it requests created in turn. Callers like the echo example rely
+    /// on `spawned()` actually running to drain an accept backlog.
+    ///
+    /// The `spawned()` dispatch (like `ready`) is counted against the
+    /// throttle's per-tick budget, so a burst of spawns (e.g. that same
+    /// edge-triggered accept loop) can't blow past `max_ops_per_tick`
+    /// just because it never goes through `queue_ready`.
+    fn spawn_machine(&mut self, event_loop: &mut EventLoop<Self>,
+        creator: M::Creator)
+    {
+        let token = Token(self.machines.len());
+        self.machines.push(None);
+        let created = {
+            let mut scope = Scope::new(token, &mut self.context, event_loop);
+            creator.create(&mut scope)
+        };
+        let machine = match created {
+            Ok(machine) => machine,
+            Err(e) => {
+                self.machines.pop();
+                let err = MachineError::new(ORIGIN_CREATE, token,
+                    Box::new(e));
+                if cfg!(feature = "log_errors") {
+                    log_error_chain(&err);
+                }
+                return;
+            }
+        };
+
+        // `decompose_draining` already turns a `Response::spawn(..)`
+        // into a logged error and no creator while draining, so this
+        // function should never be reached mid-shutdown.
+        debug_assert!(!self.draining);
+
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.ops_this_tick += 1;
+            if throttle.ops_this_tick >= throttle.max_ops_per_tick {
+                throttle.ops_this_tick = 0;
+                event_loop.timeout_ms(THROTTLE_TOKEN, 0).ok();
+            }
+        }
+
+        let response = {
+            let mut scope = Scope::new(token, &mut self.context, event_loop);
+            machine.spawned(&mut scope)
+        };
+        let (result, spawn, mtimeout, period) = decompose_draining(
+            ORIGIN_SPAWNED, token, response, self.draining);
+        match result {
+            Ok(m) => {
+                self.machines[token.as_usize()] = Some(m);
+                if let Some(time) = mtimeout {
+                    self.arm_timeout(event_loop, token, time);
+                    match period {
+                        Some(period) => { self.reschedule.insert(token, period); }
+                        None => { self.reschedule.remove(&token); }
+                    }
+                }
+                if let Some(creator) = spawn {
+                    self.spawn_machine(event_loop, creator);
+                }
+            }
+            Err(_) => {
+                self.timer.cancel(token);
+                self.reschedule.remove(&token);
+            }
+        }
+    }
+
+    /// Schedules `token` to fire `Machine::timeout` at `deadline`.
+    ///
+    /// Pushes onto the central `timer` heap rather than arming a
+    /// dedicated mio timeout for this machine; `resync_timer` makes sure
+    /// mio's one shared timeout still points at the earliest pending
+    /// deadline across every machine.
+    fn arm_timeout(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token, deadline: Time)
+    {
+        self.timer.push(token, deadline);
+        self.resync_timer(event_loop);
+    }
+
+    /// Re-arms the single mio timeout backing `timer` so it fires at the
+    /// heap's new earliest deadline, cancelling whatever was previously
+    /// armed. A no-op beyond that cancellation if the heap is empty.
+    fn resync_timer(&mut self, event_loop: &mut EventLoop<Self>) {
+        if let Some(armed) = self.armed_timer.take() {
+            event_loop.clear_timeout(&armed);
+        }
+        if let Some(deadline) = self.timer.next_deadline() {
+            let delay = millis_until(deadline);
+            self.armed_timer = event_loop.timeout_ms(TIMER_TOKEN, delay).ok();
+        }
+    }
+
+    /// Dispatches a fired mio timeout.
+    ///
+    /// `TIMER_TOKEN`, `THROTTLE_TOKEN` and `SHUTDOWN_TOKEN` are the only
+    /// tokens ever armed with mio directly; a `TIMER_TOKEN` firing means
+    /// the central
+    /// `timer` heap has one or more machine deadlines due at `fired_at`,
+    /// which are popped and handed to `fire_machine_timeout` in turn.
+    fn dispatch_timeout(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token, fired_at: Time)
+    {
+        if token == SHUTDOWN_TOKEN {
+            // The global shutdown deadline fired: force the loop to stop
+            // even if some machine is still draining, so a peer that
+            // never finishes can't block shutdown forever.
+            if self.draining {
+                event_loop.shutdown();
+            }
+            return;
+        }
+        if token == THROTTLE_TOKEN {
+            self.process_tick(event_loop);
+            return;
+        }
+        if token == TIMER_TOKEN {
+            for expired in self.timer.pop_expired(fired_at) {
+                self.fire_machine_timeout(event_loop, expired, fired_at);
+            }
+            self.resync_timer(event_loop);
+            return;
+        }
+    }
+
+    /// Fires `Machine::timeout` for `token`, whose deadline was `fired_at`.
+    ///
+    /// An interval re-registers itself at `fired_at + period` *before*
+    /// the machine even runs, so a steadily ticking machine never has to
+    /// re-arm by hand. If the machine's own response changes the timer
+    /// (another `deadline`/`interval`) or stops the machine, that
+    /// overrides the tentative re-registration we just made.
+    fn fire_machine_timeout(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token, fired_at: Time)
+    {
+        if let Some(period) = self.reschedule.get(&token).cloned() {
+            let mut next = fired_at + period;
+            if self.draining {
+                next = self.clamp_to_shutdown(next);
+            }
+            self.arm_timeout(event_loop, token, next);
+        }
+
+        let machine = match self.machines[token.as_usize()].take() {
+            Some(m) => m,
+            None => return,
+        };
+        let response = {
+            let mut scope = Scope::new(token, &mut self.context, event_loop);
+            machine.timeout(&mut scope)
+        };
+        let (result, spawned, mtimeout, period) = decompose_draining(
+            ORIGIN_TIMEOUT, token, response, self.draining);
+        match result {
+            Ok(m) => {
+                self.machines[token.as_usize()] = Some(m);
+                match (mtimeout, period) {
+                    (Some(mut time), Some(period)) => {
+                        if self.draining {
+                            time = self.clamp_to_shutdown(time);
+                        }
+                        self.arm_timeout(event_loop, token, time);
+                        self.reschedule.insert(token, period);
+                    }
+                    (Some(mut time), None) => {
+                        if self.draining {
+                            time = self.clamp_to_shutdown(time);
+                        }
+                        self.arm_timeout(event_loop, token, time);
+                        self.reschedule.remove(&token);
+                    }
+                    (None, _) => {
+                        // `Response::ok(..)` with a recurring interval
+                        // already pre-armed above keeps ticking; only a
+                        // spawn cancels the recurrence. Without a
+                        // pre-armed interval nothing stays scheduled.
+                        let keep_ticking = spawned.is_none()
+                            && self.reschedule.contains_key(&token);
+                        if !keep_ticking {
+                            self.timer.cancel(token);
+                            self.reschedule.remove(&token);
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                self.timer.cancel(token);
+                self.reschedule.remove(&token);
+                if self.draining {
+                    self.remaining -= 1;
+                    self.check_drained(event_loop);
+                }
+            }
+        }
+        if let Some(creator) = spawned {
+            self.spawn_machine(event_loop, creator);
+        }
+    }
+
+    /// Returns (creating if necessary) the `Notifier` for `token`.
+    ///
+    /// This backs `Scope::notifier()`: every machine gets its own
+    /// bounded queue, keyed by its token, rather than sharing a single
+    /// channel for the whole loop.
+    pub(crate) fn notifier(&mut self, event_loop: &EventLoop<Self>,
+        token: Token) -> Notifier<M::Notification>
+    {
+        self.notifiers.entry(token)
+            .or_insert_with(|| {
+                Notifier::new(token, DEFAULT_NOTIFY_CAPACITY,
+                    event_loop.channel())
+            })
+            .clone()
+    }
+
+    /// Drains the notify queue for `token` and dispatches each pending
+    /// message to `Machine::wakeup`, in order.
+    ///
+    /// Called whenever mio's `notify` callback fires for this handler;
+    /// a single wakeup may carry several coalesced messages if the
+    /// sender got ahead of the loop.
+    fn dispatch_wakeup(&mut self, event_loop: &mut EventLoop<Self>,
+        token: Token)
+    {
+        let messages = match self.notifiers.get(&token) {
+            Some(notifier) => notifier.drain(),
+            None => return,
+        };
+        for msg in messages {
+            let machine = match self.machines[token.as_usize()].take() {
+                Some(m) => m,
+                None => break,
+            };
+            let response = {
+                let mut scope = Scope::new(token, &mut self.context,
+                    event_loop);
+                machine.wakeup(msg, &mut scope)
+            };
+            let (result, spawn, mtimeout, period) = decompose_draining(
+                ORIGIN_WAKEUP, token, response, self.draining);
+            match result {
+                Ok(m) => {
+                    self.machines[token.as_usize()] = Some(m);
+                    if let Some(mut time) = mtimeout {
+                        if self.draining {
+                            time = self.clamp_to_shutdown(time);
+                        }
+                        self.arm_timeout(event_loop, token, time);
+                        match period {
+                            Some(period) => {
+                                self.reschedule.insert(token, period);
+                            }
+                            None => { self.reschedule.remove(&token); }
+                        }
+                    }
+                    if let Some(creator) = spawn {
+                        self.spawn_machine(event_loop, creator);
+                    }
+                }
+                Err(_) => {
+                    self.timer.cancel(token);
+                    self.reschedule.remove(&token);
+                    if self.draining {
+                        self.remaining -= 1;
+                        self.check_drained(event_loop);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use mio::{EventLoop, EventSet, Token};
+    use void::Void;
+
+    use {Creator, Machine, Response, Scope, Time};
+    use timer::TimerHeap;
+    use super::Handler;
+
+    struct Ctx;
+
+    #[derive(Debug)]
+    struct Dummy;
+
+    struct DummyCreator;
+
+    impl Creator<Ctx> for DummyCreator {
+        type Machine = Dummy;
+        type Error = Void;
+        fn create(self, _scope: &mut Scope<Ctx>) -> Result<Dummy, Void> {
+            Ok(Dummy)
+        }
+    }
+
+    impl Machine<Ctx> for Dummy {
+        type Creator = DummyCreator;
+        type Notification = Void;
+
+        fn ready(self, _events: EventSet, _scope: &mut Scope<Ctx>)
+            -> Response<Self, DummyCreator>
+        { Response::ok(self) }
+
+        fn spawned(self, _scope: &mut Scope<Ctx>)
+            -> Response<Self, DummyCreator>
+        {
+            // Arms a deadline purely so tests can tell `spawned()` (as
+            // opposed to `ready()`, which never does this) actually ran.
+            Response::ok(self).deadline(Time::now() + Duration::from_secs(60))
+        }
+
+        fn timeout(self, _scope: &mut Scope<Ctx>)
+            -> Response<Self, DummyCreator>
+        { Response::done() }
+
+        fn wakeup(self, msg: Void, _scope: &mut Scope<Ctx>)
+            -> Response<Self, DummyCreator>
+        { match msg {} }
+
+        fn shutdown(self, _scope: &mut Scope<Ctx>)
+            -> Response<Self, DummyCreator>
+        { Response::ok(self) }
+    }
+
+    /// Builds a `Handler` directly from its fields rather than through
+    /// `Handler::new`, so these tests can exercise the private shutdown/
+    /// interval/throttle bookkeeping without a real mio registration.
+    fn bare_handler(draining: bool, shutdown_deadline: Option<Time>,
+        remaining: usize) -> Handler<Ctx, Dummy>
+    {
+        Handler {
+            context: Ctx,
+            machines: Vec::new(),
+            draining: draining,
+            shutdown_deadline: shutdown_deadline,
+            remaining: remaining,
+            reschedule: HashMap::new(),
+            notifiers: HashMap::new(),
+            throttle: None,
+            timer: TimerHeap::new(),
+            armed_timer: None,
+        }
+    }
+
+    #[test]
+    fn clamp_to_shutdown_caps_a_deadline_past_the_drain_deadline() {
+        let now = Time::now();
+        let shutdown_at = now + Duration::from_secs(1);
+        let h = bare_handler(true, Some(shutdown_at), 1);
+
+        assert_eq!(h.clamp_to_shutdown(now + Duration::from_secs(5)),
+            shutdown_at);
+        assert_eq!(h.clamp_to_shutdown(now + Duration::from_millis(10)),
+            now + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn initiate_shutdown_with_no_live_machines_drains_to_zero() {
+        let mut event_loop: EventLoop<Handler<Ctx, Dummy>> =
+            EventLoop::new().unwrap();
+        let mut h = bare_handler(false, None, 0);
+
+        h.initiate_shutdown(&mut event_loop, Time::now() + Duration::from_secs(5));
+
+        assert!(h.is_draining());
+        assert_eq!(h.remaining, 0);
+    }
+
+    #[test]
+    fn arm_timeout_tracks_the_earliest_pending_deadline() {
+        let mut event_loop: EventLoop<Handler<Ctx, Dummy>> =
+            EventLoop::new().unwrap();
+        let mut h = bare_handler(false, None, 0);
+        let now = Time::now();
+
+        h.arm_timeout(&mut event_loop, Token(1), now + Duration::from_millis(50));
+        h.arm_timeout(&mut event_loop, Token(2), now + Duration::from_millis(10));
+
+        assert_eq!(h.timer.next_deadline(), Some(now + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn throttle_caps_ready_dispatches_at_the_per_tick_budget() {
+        let mut event_loop: EventLoop<Handler<Ctx, Dummy>> =
+            EventLoop::new().unwrap();
+        let mut h = bare_handler(false, None, 0);
+        h.machines.push(Some(Dummy));
+        h.machines.push(Some(Dummy));
+        h.machines.push(Some(Dummy));
+        h.with_throttle(&mut event_loop, 2, Duration::from_millis(5));
+
+        h.queue_ready(&mut event_loop, Token(0), EventSet::readable());
+        h.queue_ready(&mut event_loop, Token(1), EventSet::readable());
+        h.queue_ready(&mut event_loop, Token(2), EventSet::readable());
+
+        // The budget is 2 ops/tick: the first two dispatch immediately and
+        // the third stays queued for the next tick instead of running.
+        assert_eq!(h.throttle.as_ref().unwrap().queue.len(), 1);
+        assert!(h.machines[2].is_some());
+    }
+
+    #[test]
+    fn spawn_machine_registers_and_runs_spawned() {
+        let mut event_loop: EventLoop<Handler<Ctx, Dummy>> =
+            EventLoop::new().unwrap();
+        let mut h = bare_handler(false, None, 0);
+
+        h.spawn_machine(&mut event_loop, DummyCreator);
+
+        // The spawned machine got its own slab slot...
+        assert!(h.machines[0].is_some());
+        // ...and `Machine::spawned` (not just `Creator::create`) ran:
+        // only `spawned()` arms a deadline, `create()` doesn't.
+        assert!(h.timer.next_deadline().is_some());
+    }
+
+    #[test]
+    fn shutdown_clamps_an_already_armed_interval() {
+        let mut event_loop: EventLoop<Handler<Ctx, Dummy>> =
+            EventLoop::new().unwrap();
+        let mut h = bare_handler(false, None, 0);
+        h.machines.push(Some(Dummy));
+        let now = Time::now();
+        // A recurring interval far past where shutdown will ask for it.
+        h.reschedule.insert(Token(0), Duration::from_secs(3600));
+        h.arm_timeout(&mut event_loop, Token(0), now + Duration::from_secs(3600));
+
+        let shutdown_at = now + Duration::from_millis(50);
+        h.initiate_shutdown(&mut event_loop, shutdown_at);
+
+        // `shutdown()` returns `Response::ok(..)`, so the sweep arms the
+        // shutdown deadline, not the stale interval, and it must have
+        // dropped the old `reschedule`
+        // entry so a later `fire_machine_timeout` can't resurrect it.
+        assert!(!h.reschedule.contains_key(&Token(0)));
+        assert_eq!(h.timer.next_deadline(), Some(shutdown_at));
+    }
+}
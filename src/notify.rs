@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+
+use mio::Token;
+
+
+/// Why `Notifier::notify` failed to enqueue a message
+///
+/// Mirrors mio's old `NotifyError`: either the per-machine queue is
+/// full (the machine isn't draining its wakeups fast enough), the
+/// handler has already gone away, or waking it up hit an I/O error.
+#[derive(Debug)]
+pub enum NotifyError<T> {
+    /// The queue for this token already holds as many messages as it is
+    /// allowed to; the message is handed back to the caller.
+    Full(T),
+    /// The event loop (and therefore the machine) is gone.
+    Closed(T),
+    /// Waking the event loop up failed at the OS level; the message is
+    /// handed back to the caller along with the underlying error.
+    Io(T, io::Error),
+}
+
+impl<T: fmt::Debug> fmt::Display for NotifyError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotifyError::Full(..) => write!(f, "notify queue is full"),
+            NotifyError::Closed(..) => write!(f, "event loop is closed"),
+            NotifyError::Io(_, ref e) => write!(f, "error waking event loop: {}", e),
+        }
+    }
+}
+
+impl<T: fmt::Debug> Error for NotifyError<T> {
+    fn description(&self) -> &str {
+        match *self {
+            NotifyError::Full(..) => "notify queue is full",
+            NotifyError::Closed(..) => "event loop is closed",
+            NotifyError::Io(..) => "error waking event loop",
+        }
+    }
+}
+
+struct Queue<T> {
+    capacity: usize,
+    messages: Mutex<VecDeque<T>>,
+}
+
+/// A `Send + Clone` handle that lets another thread hand a typed message
+/// to a specific state machine and wake the event loop up to deliver it.
+///
+/// Obtained via `Scope::notifier()`. This is rotor's replacement for
+/// mio's deprecated `Sender`/`notify`: instead of a single untyped
+/// channel shared by the whole loop, every machine gets its own bounded
+/// queue keyed by its `Token`, and `Machine::wakeup` receives the
+/// message directly rather than having to go look it up.
+pub struct Notifier<T> {
+    token: Token,
+    queue: Arc<Queue<T>>,
+    channel: ::mio::Sender<Token>,
+}
+
+impl<T> Clone for Notifier<T> {
+    fn clone(&self) -> Notifier<T> {
+        Notifier {
+            token: self.token,
+            queue: self.queue.clone(),
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Notifier<T> {
+    /// Creates a notifier for `token` backed by a queue of `capacity`
+    /// messages, using `channel` to wake the event loop.
+    pub fn new(token: Token, capacity: usize, channel: ::mio::Sender<Token>)
+        -> Notifier<T>
+    {
+        Notifier {
+            token: token,
+            queue: Arc::new(Queue {
+                capacity: capacity,
+                messages: Mutex::new(VecDeque::new()),
+            }),
+            channel: channel,
+        }
+    }
+
+    /// Enqueue `msg` for the machine this notifier was created for and
+    /// wake the event loop so `Machine::wakeup` gets called with it.
+    pub fn notify(&self, msg: T) -> Result<(), NotifyError<T>> {
+        // Hold the lock across both the push and the send: if we
+        // dropped it in between, another thread's concurrent `notify`
+        // could interleave and `pop_back` would recover *their* message
+        // instead of ours on failure.
+        let mut queue = self.queue.messages.lock().unwrap();
+        if queue.len() >= self.queue.capacity {
+            return Err(NotifyError::Full(msg));
+        }
+        queue.push_back(msg);
+        match self.channel.send(self.token) {
+            Ok(()) => Ok(()),
+            Err(::mio::NotifyError::Closed(_)) => {
+                Err(NotifyError::Closed(queue.pop_back().unwrap()))
+            }
+            Err(::mio::NotifyError::Io(e)) => {
+                Err(NotifyError::Io(queue.pop_back().unwrap(), e))
+            }
+            Err(::mio::NotifyError::Full(_)) => {
+                Err(NotifyError::Full(queue.pop_back().unwrap()))
+            }
+        }
+    }
+
+    /// Drain every message enqueued so far for this machine's token.
+    ///
+    /// Called by `Handler` on loop wakeup, once per `Token` that was
+    /// signalled, before dispatching to `Machine::wakeup`.
+    pub fn drain(&self) -> Vec<T> {
+        self.queue.messages.lock().unwrap().drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::{EventLoop, Handler, Token};
+
+    use super::{Notifier, NotifyError};
+
+    /// Stands in for the real `rotor::Handler`, just to get a
+    /// `Token`-keyed `mio::Sender` out of `EventLoop::channel()`.
+    struct NullHandler;
+    impl Handler for NullHandler {
+        type Timeout = ();
+        type Message = Token;
+    }
+
+    fn channel() -> ::mio::Sender<Token> {
+        let event_loop: EventLoop<NullHandler> = EventLoop::new().unwrap();
+        event_loop.channel()
+    }
+
+    #[test]
+    fn drain_returns_messages_in_order() {
+        let notifier = Notifier::new(Token(1), 2, channel());
+        notifier.notify("a").unwrap();
+        notifier.notify("b").unwrap();
+        assert_eq!(notifier.drain(), vec!["a", "b"]);
+        assert_eq!(notifier.drain(), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn notify_past_capacity_returns_the_message_back() {
+        let notifier = Notifier::new(Token(1), 1, channel());
+        notifier.notify("a").unwrap();
+        match notifier.notify("b") {
+            Err(NotifyError::Full(msg)) => assert_eq!(msg, "b"),
+            other => panic!("expected NotifyError::Full, got {:?}", other),
+        }
+        // The rejected message was never enqueued.
+        assert_eq!(notifier.drain(), vec!["a"]);
+    }
+
+    #[test]
+    fn notify_after_event_loop_is_dropped_returns_closed() {
+        let notifier = Notifier::new(Token(1), 4, channel());
+        match notifier.notify("a") {
+            Err(NotifyError::Closed(msg)) => assert_eq!(msg, "a"),
+            other => panic!("expected NotifyError::Closed, got {:?}", other),
+        }
+        // A failed send must not leave the message stuck in the queue.
+        assert_eq!(notifier.drain(), Vec::<&str>::new());
+    }
+}
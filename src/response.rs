@@ -1,15 +1,18 @@
 use std::fmt::Debug;
 use std::error::Error;
+use std::time::Duration;
 
 use mio::Token;
 
 use {Response, Time};
+use error::{MachineError, log_error_chain};
 
 
 #[derive(Debug)]
 pub enum ResponseImpl<M, N> {
     Normal(M),
     Deadline(M, Time),
+    Interval(M, Time, Duration),
     Spawn(M, N),
     Error(Box<Error>),
     Done,
@@ -42,6 +45,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         let imp = match self.0 {
             ResponseImpl::Normal(x) => ResponseImpl::Deadline(x, time),
             ResponseImpl::Deadline(x, _) => ResponseImpl::Deadline(x, time),
+            ResponseImpl::Interval(x, _, _) => ResponseImpl::Deadline(x, time),
             ResponseImpl::Spawn(..) => {
                 panic!("You can't attach a deadline/timeout to the \
                     Response::spawn(). The `spawn` action is synchronous \
@@ -59,6 +63,41 @@ impl<M: Sized, N:Sized> Response<M, N> {
         };
         Response(imp)
     }
+
+    /// Arm a recurring timer, firing `Machine::timeout` first at
+    /// `first` and then every `period` after that.
+    ///
+    /// Unlike `deadline`, the timer re-arms itself automatically: there
+    /// is no need to call `interval` (or `deadline`) again from inside
+    /// `timeout` just to keep the tick going. Returning anything other
+    /// than `Response::ok(..)`/`Response::interval(..)` from `timeout`
+    /// cancels the recurrence.
+    pub fn interval(self, first: Time, period: Duration) -> Response<M, N> {
+        let imp = match self.0 {
+            ResponseImpl::Normal(x) => ResponseImpl::Interval(x, first, period),
+            ResponseImpl::Deadline(x, _) => {
+                ResponseImpl::Interval(x, first, period)
+            }
+            ResponseImpl::Interval(x, _, _) => {
+                ResponseImpl::Interval(x, first, period)
+            }
+            ResponseImpl::Spawn(..) => {
+                panic!("You can't attach an interval to the \
+                    Response::spawn(). The `spawn` action is synchronous \
+                    you must set an interval in the `spawned` handler."); }
+            ResponseImpl::Done => {
+                panic!("You can't attach an interval to \
+                    Response::done() as it's useless. \
+                    Timeout will never happen");
+            }
+            ResponseImpl::Error(_) => {
+                panic!("You can't attach an interval to \
+                    Response::error(_) as it's useless. \
+                    Timeout will never happen");
+            }
+        };
+        Response(imp)
+    }
     /// Maps state machine and/or spawned result with a function
     ///
     /// Usually it's okay to use constructor of wrapper state machine
@@ -72,6 +111,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
             Deadline(m, time) => Deadline(self_mapper(m), time),
+            Interval(m, time, period) => Interval(self_mapper(m), time, period),
             Spawn(m, n) => Spawn(self_mapper(m), result_mapper(n)),
             Done => Done,
             Error(e) => Error(e),
@@ -89,6 +129,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
             Deadline(m, time) => Deadline(self_mapper(m), time),
+            Interval(m, time, period) => Interval(self_mapper(m), time, period),
             Spawn(m, n) => Spawn(self_mapper(m), n),
             Done => Done,
             Error(e) => Error(e),
@@ -105,6 +146,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         match self.0 {
             Normal(..) => false,
             Deadline(..) => false,
+            Interval(..) => false,
             Spawn(..) => false,
             Done => true,
             Error(..) => true,
@@ -121,6 +163,7 @@ impl<M: Sized, N:Sized> Response<M, N> {
         match self.0 {
             Normal(..) => None,
             Deadline(..) => None,
+            Interval(..) => None,
             Spawn(..) => None,
             Done => None,
             Error(ref e) => Some(&**e),
@@ -138,6 +181,7 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
         match self.0 {
             ResponseImpl::Normal(x) => x,
             ResponseImpl::Deadline(x, _) => x,
+            ResponseImpl::Interval(x, _, _) => x,
             me => panic!("expected machine (`Response::ok(x)`), \
                 got {:?} instead", me),
         }
@@ -180,19 +224,55 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
     }
 }
 
-pub fn decompose<M, N>(token: Token, res: Response<M, N>)
-    -> (Result<M, Option<Box<Error>>>, Option<N>, Option<Time>)
+pub fn decompose<M, N>(origin: &'static str, token: Token, res: Response<M, N>)
+    -> (Result<M, Option<MachineError>>, Option<N>, Option<Time>, Option<Duration>)
+{
+    decompose_draining(origin, token, res, false)
+}
+
+/// Like `decompose` but aware of the handler's shutdown ("draining")
+/// state.
+///
+/// While draining, no new machine is allowed to spawn: a
+/// `ResponseImpl::Spawn` is logged as an error and the spawn is dropped,
+/// keeping the original machine alive rather than handing the handler a
+/// machine it has to tear down again.
+///
+/// The fourth element of the returned tuple is the interval's period,
+/// present only for `ResponseImpl::Interval`; callers use it to
+/// re-register the timer at `fired_at + period` once it fires.
+///
+/// `origin` names the handler method that was running (`"ready"`,
+/// `"timeout"`, `"wakeup"`, `"spawned"`, `"create"` or `"shutdown"`); any leaf error
+/// is wrapped in a `MachineError` carrying that origin and the token,
+/// so `log_errors` can print the full cause chain instead of just the
+/// leaf message.
+pub fn decompose_draining<M, N>(origin: &'static str, token: Token,
+    res: Response<M, N>, draining: bool)
+    -> (Result<M, Option<MachineError>>, Option<N>, Option<Time>, Option<Duration>)
 {
     match res.0 {
-        ResponseImpl::Normal(m) => (Ok(m), None, None),
-        ResponseImpl::Deadline(m, time) => (Ok(m), None, Some(time)),
-        ResponseImpl::Spawn(m, n) => (Ok(m), Some(n), None),
-        ResponseImpl::Done => (Err(None), None, None),
+        ResponseImpl::Normal(m) => (Ok(m), None, None, None),
+        ResponseImpl::Deadline(m, time) => (Ok(m), None, Some(time), None),
+        ResponseImpl::Interval(m, time, period) => {
+            (Ok(m), None, Some(time), Some(period))
+        }
+        ResponseImpl::Spawn(m, n) => {
+            if draining {
+                error!("State machine {:?} tried to spawn while the \
+                    handler is shutting down; ignoring", token);
+                (Ok(m), None, None, None)
+            } else {
+                (Ok(m), Some(n), None, None)
+            }
+        }
+        ResponseImpl::Done => (Err(None), None, None, None),
         ResponseImpl::Error(e) => {
+            let err = MachineError::new(origin, token, e);
             if cfg!(feature = "log_errors") {
-                warn!("State machine {:?} exited with error: {}", token, e);
+                log_error_chain(&err);
             }
-            (Err(Some(e)), None, None)
+            (Err(Some(err)), None, None, None)
         }
     }
 }
@@ -203,6 +283,9 @@ mod tests {
 
     #[test]
     fn size_of_response() {
-        assert_eq!(::std::mem::size_of::<Response<u64, u64>>(), 24)
+        // `ResponseImpl::Interval(M, Time, Duration)` is now the widest
+        // variant (wider than `Error`'s boxed trait object), so this
+        // grew from 24 bytes when `Response::interval` was added.
+        assert_eq!(::std::mem::size_of::<Response<u64, u64>>(), 32)
     }
 }
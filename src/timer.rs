@@ -0,0 +1,176 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use mio::Token;
+
+use Time;
+
+
+/// One scheduled wakeup: `Handler` fires `Machine::timeout` for `token`
+/// once `time` is reached, provided `generation` still matches the
+/// token's current generation in the owning `TimerHeap`.
+#[derive(Debug)]
+struct Entry {
+    time: Time,
+    token: Token,
+    generation: u64,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.time == other.time
+    }
+}
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        // `BinaryHeap` is a max-heap; reverse the comparison so the
+        // earliest `time` sorts to the top.
+        other.time.cmp(&self.time)
+    }
+}
+
+/// A central min-heap of pending machine deadlines, modeled on
+/// tokio-core's `Heap`/`Slot`.
+///
+/// Instead of `Handler` arming a separate mio-level timeout per machine
+/// (`O(n)` live timers for `n` machines), exactly one mio timeout is
+/// kept armed for the earliest entry here. Cancelling a deadline --
+/// because the machine re-armed it or was torn down -- just bumps the
+/// token's generation counter rather than searching the heap for the
+/// stale node; the orphaned entry is skipped the next time it rises to
+/// the top instead of being removed eagerly, which keeps `push` and
+/// `cancel` both `O(log n)` with no mid-heap deletions on the hot path.
+pub struct TimerHeap {
+    entries: BinaryHeap<Entry>,
+    generations: HashMap<Token, u64>,
+}
+
+impl TimerHeap {
+    pub fn new() -> TimerHeap {
+        TimerHeap {
+            entries: BinaryHeap::new(),
+            generations: HashMap::new(),
+        }
+    }
+
+    /// Schedules `token` to fire at `time`, invalidating whatever entry
+    /// was previously scheduled for it.
+    pub fn push(&mut self, token: Token, time: Time) {
+        let generation = self.generations.entry(token).or_insert(0);
+        *generation += 1;
+        self.entries.push(Entry {
+            time: time,
+            token: token,
+            generation: *generation,
+        });
+    }
+
+    /// Invalidates whatever is currently scheduled for `token`, without
+    /// touching the heap; the orphaned entry is dropped lazily the next
+    /// time it would otherwise fire.
+    pub fn cancel(&mut self, token: Token) {
+        *self.generations.entry(token).or_insert(0) += 1;
+    }
+
+    /// The time of the earliest entry that hasn't been invalidated, if
+    /// any. `Handler` uses this to compute how long mio should block in
+    /// its next poll.
+    pub fn next_deadline(&mut self) -> Option<Time> {
+        self.drop_stale();
+        self.entries.peek().map(|e| e.time)
+    }
+
+    /// Removes and returns the token of every entry due at or before
+    /// `now`, skipping (and dropping) any a later `push`/`cancel`
+    /// invalidated in the meantime.
+    pub fn pop_expired(&mut self, now: Time) -> Vec<Token> {
+        let mut fired = Vec::new();
+        loop {
+            self.drop_stale();
+            match self.entries.peek() {
+                Some(e) if e.time <= now => {}
+                _ => break,
+            }
+            fired.push(self.entries.pop().unwrap().token);
+        }
+        fired
+    }
+
+    /// Pops every entry currently on top of the heap whose generation
+    /// no longer matches the token's latest, i.e. anything `cancel` or
+    /// a newer `push` has orphaned.
+    fn drop_stale(&mut self) {
+        loop {
+            let stale = match self.entries.peek() {
+                Some(e) => {
+                    self.generations.get(&e.token)
+                        .map_or(true, |g| *g != e.generation)
+                }
+                None => return,
+            };
+            if !stale {
+                return;
+            }
+            self.entries.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mio::Token;
+
+    use Time;
+    use super::TimerHeap;
+
+    #[test]
+    fn pop_expired_returns_entries_earliest_first() {
+        let mut heap = TimerHeap::new();
+        let now = Time::now();
+        heap.push(Token(2), now + Duration::from_millis(20));
+        heap.push(Token(1), now + Duration::from_millis(10));
+        heap.push(Token(3), now + Duration::from_millis(30));
+
+        let fired = heap.pop_expired(now + Duration::from_millis(25));
+        assert_eq!(fired, vec![Token(1), Token(2)]);
+        assert_eq!(heap.next_deadline(), Some(now + Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn cancel_orphans_the_entry_instead_of_removing_it() {
+        let mut heap = TimerHeap::new();
+        let now = Time::now();
+        heap.push(Token(1), now + Duration::from_millis(10));
+
+        heap.cancel(Token(1));
+
+        assert_eq!(heap.next_deadline(), None);
+        assert_eq!(heap.pop_expired(now + Duration::from_millis(10)), vec![]);
+    }
+
+    #[test]
+    fn re_pushing_a_token_invalidates_its_previous_entry() {
+        let mut heap = TimerHeap::new();
+        let now = Time::now();
+        heap.push(Token(1), now + Duration::from_millis(10));
+        // The machine re-armed its own deadline before the first one fired;
+        // only the newest entry should ever be delivered.
+        heap.push(Token(1), now + Duration::from_millis(50));
+
+        let fired = heap.pop_expired(now + Duration::from_millis(10));
+        assert_eq!(fired, vec![]);
+
+        let fired = heap.pop_expired(now + Duration::from_millis(50));
+        assert_eq!(fired, vec![Token(1)]);
+    }
+}